@@ -0,0 +1,102 @@
+// A backend abstraction so `dot`/`matmul` can target Metal or a CPU fallback
+// behind one interface, instead of `cpu_dot`/`gpu_dot` being entirely
+// separate call paths. Lets the rest of the program run, and be tested,
+// on machines without a Metal device.
+use crate::{cpu_dot, cpu_matmul, gpu_dot, gpu_dot_timed, gpu_matmul, gpu_setup, GPU};
+use metal::Device;
+use std::time::Duration;
+
+// Types ----------------------------------------------------------------------
+pub trait Backend {
+    fn dot(&self, a: &[f32], b: &[f32]) -> f32;
+    fn matmul(&self, a: &[f32], b: &[f32], m: u64, n: u64, k: u64) -> Vec<f32>;
+
+    // Like `dot`, but also reports the measured kernel time where a backend
+    // can tell dispatch time apart from kernel time (only Metal can, so far).
+    fn dot_timed(&self, a: &[f32], b: &[f32]) -> (f32, Option<Duration>) {
+        (self.dot(a, b), None)
+    }
+}
+
+pub struct MetalBackend {
+    gpu: GPU,
+}
+
+pub struct CpuBackend;
+
+// Functions --------------------------------------------------------------
+impl MetalBackend {
+    pub fn new(gpu: GPU) -> MetalBackend {
+        MetalBackend { gpu }
+    }
+}
+
+impl Backend for MetalBackend {
+    fn dot(&self, a: &[f32], b: &[f32]) -> f32 {
+        gpu_dot(a, b, &self.gpu)
+    }
+
+    fn matmul(&self, a: &[f32], b: &[f32], m: u64, n: u64, k: u64) -> Vec<f32> {
+        gpu_matmul(a, b, m, n, k, &self.gpu)
+    }
+
+    fn dot_timed(&self, a: &[f32], b: &[f32]) -> (f32, Option<Duration>) {
+        gpu_dot_timed(a, b, &self.gpu)
+    }
+}
+
+impl Backend for CpuBackend {
+    fn dot(&self, a: &[f32], b: &[f32]) -> f32 {
+        cpu_dot(a, b)
+    }
+
+    fn matmul(&self, a: &[f32], b: &[f32], m: u64, n: u64, k: u64) -> Vec<f32> {
+        cpu_matmul(a, b, m, n, k)
+    }
+}
+
+// Probes for a Metal device and falls back to the CPU backend when absent.
+pub fn backend() -> Box<dyn Backend> {
+    if Device::system_default().is_some() {
+        Box::new(MetalBackend::new(gpu_setup()))
+    } else {
+        Box::new(CpuBackend)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dot_approx_eq;
+
+    #[test]
+    fn cpu_backend_dot_matches_cpu_dot() {
+        let a = [1.0, 2.0, 3.0];
+        let b = [4.0, 5.0, 6.0];
+        assert_eq!(CpuBackend.dot(&a, &b), cpu_dot(&a, &b));
+    }
+
+    #[test]
+    fn cpu_backend_matmul_matches_cpu_matmul() {
+        let a = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        let b = [7.0, 8.0, 9.0, 10.0, 11.0, 12.0];
+        assert_eq!(CpuBackend.matmul(&a, &b, 2, 2, 3), cpu_matmul(&a, &b, 2, 2, 3));
+    }
+
+    #[test]
+    fn cpu_backend_dot_timed_reports_no_kernel_time() {
+        let (result, kernel_elapsed) = CpuBackend.dot_timed(&[1.0, 2.0], &[3.0, 4.0]);
+        assert_eq!(result, 11.0);
+        assert!(kernel_elapsed.is_none());
+    }
+
+    // backend() probes for a real Metal device internally, so this runs (and
+    // stays correct) with or without a GPU present.
+    #[test]
+    fn backend_factory_agrees_with_cpu_backend() {
+        let a = [1.0, -2.0, 3.5];
+        let b = [0.5, 4.0, -1.0];
+        let result = backend().dot(&a, &b);
+        assert!(dot_approx_eq(result, CpuBackend.dot(&a, &b), a.len()));
+    }
+}
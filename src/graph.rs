@@ -0,0 +1,158 @@
+// A small compute graph so callers can chain ops (mul, add, matmul) against
+// the GPU without hand-wiring an encoder per op. An op's inputs are either a
+// fresh leaf `Tensor` or a reference to an earlier op's output
+// (`Input::Node`), so e.g. `mul(add(a, b), c)` encodes as two dispatches
+// against one command buffer with no CPU round-trip between them. All
+// queued ops are encoded in push order and synchronized once, at `compute`.
+//
+// There's no Dot op here: dot_product's kernel only finishes the reduction
+// to one partial sum per threadgroup, and turning that into a scalar needs
+// either a CPU-side sum (a round-trip this graph exists to avoid) or a
+// dedicated reduce-to-one kernel this graph doesn't have. gpu_dot_timed
+// still calls dot_product directly for that reason.
+use {
+    crate::{gpu_write, size_1d, size_2d, GPU},
+    metal::*,
+    std::{ffi, mem, slice},
+};
+
+// Types ------------------------------------------------------------------
+pub struct Tensor {
+    pub rows: u64,
+    pub cols: u64,
+    pub buf: Buffer,
+}
+
+pub enum Input {
+    Tensor(Tensor),
+    // Index of an earlier op in the same graph whose output feeds this one.
+    Node(usize),
+}
+
+pub enum Op {
+    ElementwiseMul(Input, Input),
+    Add(Input, Input),
+    MatMul(Input, Input),
+}
+
+pub struct Graph {
+    ops: Vec<Op>,
+}
+
+// Functions ----------------------------------------------------------------
+impl Tensor {
+    pub fn from_slice(gpu: &GPU, rows: u64, cols: u64, data: &[f32]) -> Tensor {
+        let buf = gpu.acquire(data.len() * mem::size_of::<f32>());
+        gpu_write(&buf, data);
+        Tensor { rows, cols, buf }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.rows * self.cols
+    }
+
+    pub fn to_vec(&self) -> Vec<f32> {
+        unsafe { slice::from_raw_parts(self.buf.contents() as *const f32, self.len() as usize) }.to_vec()
+    }
+}
+
+impl From<Tensor> for Input {
+    fn from(tensor: Tensor) -> Input {
+        Input::Tensor(tensor)
+    }
+}
+
+impl Graph {
+    pub fn new() -> Graph {
+        Graph { ops: Vec::new() }
+    }
+
+    // Returns the node index this op's output can be referenced by, via
+    // `Input::Node(idx)`, in a later op pushed onto the same graph.
+    pub fn push(&mut self, op: Op) -> usize {
+        self.ops.push(op);
+        self.ops.len() - 1
+    }
+
+    // Consumes the graph so leaf input buffers can be handed back to the pool
+    // once the command buffer finishes, instead of being dropped (and their
+    // underlying Metal allocations freed) along with the graph.
+    pub fn compute(self, gpu: &GPU) -> Vec<Tensor> {
+        let threads_per_threadgroup: u64 = 64;
+        let tile: u64 = 16;
+        let command_buffer = gpu.cmds.new_command_buffer();
+        let mut results: Vec<Tensor> = Vec::with_capacity(self.ops.len());
+
+        for op in &self.ops {
+            let (fn_name, a, b) = match op {
+                Op::ElementwiseMul(a, b) => ("elementwise_mul", a, b),
+                Op::Add(a, b) => ("elementwise_add", a, b),
+                Op::MatMul(a, b) => ("matmul", a, b),
+            };
+            let (buf_a, a_rows, a_cols) = resolve(a, &results);
+            let (buf_b, _, b_cols) = resolve(b, &results);
+
+            let encoder = command_buffer.new_compute_command_encoder();
+            encoder.set_compute_pipeline_state(gpu.pipeline(fn_name));
+            encoder.set_buffer(0, Some(buf_a), 0);
+            encoder.set_buffer(1, Some(buf_b), 0);
+
+            let tensor = if let Op::MatMul(..) = op {
+                let (m, k, n) = (a_rows, a_cols, b_cols);
+                let out = gpu.acquire((m * n) as usize * mem::size_of::<f32>());
+                let (m32, n32, k32) = (m as u32, n as u32, k as u32);
+                encoder.set_buffer(2, Some(&out), 0);
+                encoder.set_bytes(3, mem::size_of::<u32>() as u64, &m32 as *const u32 as *const ffi::c_void);
+                encoder.set_bytes(4, mem::size_of::<u32>() as u64, &n32 as *const u32 as *const ffi::c_void);
+                encoder.set_bytes(5, mem::size_of::<u32>() as u64, &k32 as *const u32 as *const ffi::c_void);
+                let groups = size_2d((n as f64 / tile as f64).ceil() as u64, (m as f64 / tile as f64).ceil() as u64);
+                encoder.dispatch_thread_groups(groups, size_2d(tile, tile));
+                encoder.end_encoding();
+                Tensor { rows: m, cols: n, buf: out }
+            } else {
+                let len = a_rows * a_cols;
+                let out = gpu.acquire(len as usize * mem::size_of::<f32>());
+                let len32 = len as u32;
+                encoder.set_buffer(2, Some(&out), 0);
+                encoder.set_bytes(3, mem::size_of::<u32>() as u64, &len32 as *const u32 as *const ffi::c_void);
+                let groups = threadgroups(len, threads_per_threadgroup);
+                encoder.dispatch_thread_groups(size_1d(groups), size_1d(threads_per_threadgroup));
+                encoder.end_encoding();
+                Tensor { rows: a_rows, cols: a_cols, buf: out }
+            };
+
+            results.push(tensor);
+        }
+
+        command_buffer.commit();
+        command_buffer.wait_until_completed();
+
+        // Leaf inputs are only read once, by the op they were built for.
+        // Node inputs are earlier entries in `results`, already owned by the
+        // caller through the returned Vec, so leave those alone.
+        for op in self.ops {
+            let (a, b) = match op {
+                Op::ElementwiseMul(a, b) | Op::Add(a, b) | Op::MatMul(a, b) => (a, b),
+            };
+            for input in [a, b] {
+                if let Input::Tensor(tensor) = input {
+                    gpu.release(tensor.buf);
+                }
+            }
+        }
+
+        results
+    }
+}
+
+fn resolve<'a>(input: &'a Input, results: &'a [Tensor]) -> (&'a Buffer, u64, u64) {
+    let tensor = match input {
+        Input::Tensor(tensor) => tensor,
+        Input::Node(index) => &results[*index],
+    };
+    (&tensor.buf, tensor.rows, tensor.cols)
+}
+
+fn threadgroups(len: u64, threads_per_threadgroup: u64) -> u64 {
+    (len as f64 / threads_per_threadgroup as f64).ceil() as u64
+}
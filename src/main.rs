@@ -1,53 +1,121 @@
 // Imports --------------------------------------------------------------------
 extern crate metal;
+extern crate objc;
 extern crate rand;
+mod backend;
+mod graph;
+
 use {
+    backend::Backend,
     metal::*,
+    objc::rc::autoreleasepool,
     rand::Rng,
-    std::{ffi, slice, sync::Arc},
+    std::{
+        collections::HashMap,
+        ffi, mem, slice,
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
 };
 
 // Types ----------------------------------------------------------------------
 pub struct GPU {
     dev: Arc<Device>,
-    cmds: Arc<CommandQueue>,
-    state: Arc<ComputePipelineState>,
+    pub(crate) cmds: Arc<CommandQueue>,
+    pipelines: HashMap<String, Arc<ComputePipelineState>>,
+    counter_sample_buffer: Option<CounterSampleBuffer>,
+    // (cpu_ticks, gpu_ticks) captured once at setup, far enough in the past
+    // by the time we read a kernel's ticks that the CPU/GPU clock ratio
+    // derived from it is stable, instead of recomputing it over a near-zero
+    // span on every call.
+    timestamp_calibration: Option<(u64, u64)>,
+    pool: Mutex<HashMap<usize, Vec<Buffer>>>,
+}
+
+impl GPU {
+    // Takes a buffer of at least `byte_len` from the pool, allocating one only
+    // on a pool miss, so the hot loop reuses buffers instead of churning them.
+    pub fn acquire(&self, byte_len: usize) -> Buffer {
+        let mut pool = self.pool.lock().unwrap();
+        match pool.get_mut(&byte_len).and_then(Vec::pop) {
+            Some(buf) => buf,
+            None => gpu_alloc(&self.dev, byte_len as u64),
+        }
+    }
+
+    pub fn release(&self, buf: Buffer) {
+        let byte_len = buf.length() as usize;
+        self.pool.lock().unwrap().entry(byte_len).or_insert_with(Vec::new).push(buf);
+    }
+
+    pub(crate) fn pipeline(&self, fn_name: &str) -> &Arc<ComputePipelineState> {
+        self.pipelines
+            .get(fn_name)
+            .unwrap_or_else(|| panic!("no pipeline registered for `{}`", fn_name))
+    }
 }
 
 // Public Functions -----------------------------------------------------------
 pub fn main() {
     use std::time::Instant;
     let size = 1_000_000_000;
-    let gpu = gpu_setup();
+    // Picks Metal when available and falls back to the CPU backend
+    // otherwise, so this loop runs the same either way.
+    let backend = backend::backend();
+    let cpu = backend::CpuBackend;
+
     loop {
-        let rand_start = Instant::now();
-        let a: Vec<f32> = (0..size)
-            .map(|_| rand::thread_rng().gen_range(-1.0..=1.0))
-            .collect();
-        let b: Vec<f32> = (0..size)
-            .map(|_| rand::thread_rng().gen_range(-1.0..=1.0))
-            .collect();
-        let rand_elapsed = rand_start.elapsed();
-
-        let gpu_start = Instant::now();
-        let result = gpu_dot(&a, &b, &gpu);
-        let gpu_elapsed = gpu_start.elapsed();
-
-        let cpu_start = Instant::now();
-        let cpu_result = cpu_dot(&a, &b);
-        let cpu_elapsed = cpu_start.elapsed();
-        assert_eq!(result, cpu_result);
-
-        println!("Matrix time : {:?}", rand_elapsed);
-        println!();
-        println!("GPU dot     : {}", result);
-        println!("GPU time    : {:?}", gpu_elapsed);
-        println!();
-        println!("CPU dot     : {}", cpu_result);
-        println!("CPU time    : {:?}", cpu_elapsed);
+        autoreleasepool(|| {
+            let rand_start = Instant::now();
+            let a: Vec<f32> = (0..size)
+                .map(|_| rand::thread_rng().gen_range(-1.0..=1.0))
+                .collect();
+            let b: Vec<f32> = (0..size)
+                .map(|_| rand::thread_rng().gen_range(-1.0..=1.0))
+                .collect();
+            let rand_elapsed = rand_start.elapsed();
+
+            let dot_start = Instant::now();
+            let (result, kernel_elapsed) = backend.dot_timed(&a, &b);
+            let dot_elapsed = dot_start.elapsed();
+
+            let cpu_start = Instant::now();
+            let cpu_result = cpu.dot(&a, &b);
+            let cpu_elapsed = cpu_start.elapsed();
+            assert!(
+                dot_approx_eq(result, cpu_result, a.len()),
+                "gpu/cpu dot mismatch: {} vs {}",
+                result,
+                cpu_result
+            );
+
+            println!("Matrix time : {:?}", rand_elapsed);
+            println!();
+            println!("Dot         : {}", result);
+            match kernel_elapsed {
+                Some(kernel) => println!("Dot time    : {:?} (wall), {:?} (kernel)", dot_elapsed, kernel),
+                None => println!("Dot time    : {:?}", dot_elapsed),
+            }
+            println!();
+            println!("CPU dot     : {}", cpu_result);
+            println!("CPU time    : {:?}", cpu_elapsed);
+        });
     }
 }
 
+// The GPU sums per-threadgroup partials (each a tree reduction) while
+// `cpu_dot` accumulates sequentially, so the two sums take different
+// addition orders and essentially never match bit-for-bit. Random-sign
+// rounding error in a length-`len` sum grows roughly with sqrt(len) machine
+// epsilons, so a constant tolerance tuned for a handful of elements is far
+// too tight at the ~1e9-element scale `main` actually runs at — scale the
+// tolerance with len instead, with a wide safety margin since GPU and CPU
+// accumulate in different orders.
+pub(crate) fn dot_approx_eq(a: f32, b: f32, len: usize) -> bool {
+    let epsilon = 8.0 * (len as f32).sqrt() * f32::EPSILON;
+    (a - b).abs() <= epsilon * a.abs().max(b.abs()).max(1.0)
+}
+
 pub fn cpu_dot(a: &[f32], b: &[f32]) -> f32 {
     let mut result = 0.0;
     for i in 0..a.len() {
@@ -56,60 +124,176 @@ pub fn cpu_dot(a: &[f32], b: &[f32]) -> f32 {
     result
 }
 
+pub fn cpu_matmul(a: &[f32], b: &[f32], m: u64, n: u64, k: u64) -> Vec<f32> {
+    let (m, n, k) = (m as usize, n as usize, k as usize);
+    let mut result = vec![0.0; m * n];
+    for row in 0..m {
+        for col in 0..n {
+            let mut acc = 0.0;
+            for i in 0..k {
+                acc += a[row * k + i] * b[i * n + col];
+            }
+            result[row * n + col] = acc;
+        }
+    }
+    result
+}
+
 pub fn gpu_dot(a: &[f32], b: &[f32], gpu: &GPU) -> f32 {
-    let command_buffer = gpu.cmds.new_command_buffer();
-    let encoder = command_buffer.new_compute_command_encoder();
-    let buf_result = gpu_mem(Arc::clone(&gpu.dev), encoder, a, b);
-    encoder.set_compute_pipeline_state(&gpu.state);
-    gpu_run(command_buffer, encoder, a.len());
-    gpu_result(buf_result, a.len())
+    gpu_dot_timed(a, b, gpu).0
+}
+
+pub fn gpu_dot_timed(a: &[f32], b: &[f32], gpu: &GPU) -> (f32, Option<Duration>) {
+    autoreleasepool(|| {
+        let threads_per_threadgroup: u64 = 64;
+        let threadgroups = (a.len() as f64 / threads_per_threadgroup as f64).ceil() as u64;
+
+        let command_buffer = gpu.cmds.new_command_buffer();
+        let encoder = command_buffer.new_compute_command_encoder();
+        if let Some(sample_buffer) = &gpu.counter_sample_buffer {
+            encoder.sample_counters_in_buffer(sample_buffer, 0, true);
+        }
+        let (buf_a, buf_b, buf_result) = gpu_mem(gpu, encoder, a, b, threadgroups);
+        encoder.set_compute_pipeline_state(gpu.pipeline("dot_product"));
+        encoder.dispatch_thread_groups(size_1d(threadgroups), size_1d(threads_per_threadgroup));
+        if let Some(sample_buffer) = &gpu.counter_sample_buffer {
+            encoder.sample_counters_in_buffer(sample_buffer, 1, true);
+        }
+        encoder.end_encoding();
+        command_buffer.commit();
+        command_buffer.wait_until_completed();
+
+        let kernel_elapsed = gpu
+            .counter_sample_buffer
+            .as_ref()
+            .and_then(|buf| gpu_kernel_duration(gpu, buf));
+
+        gpu.release(buf_a);
+        gpu.release(buf_b);
+        let result = gpu_result(&buf_result, threadgroups as usize);
+        gpu.release(buf_result);
+
+        (result, kernel_elapsed)
+    })
 }
 
 pub fn gpu_setup() -> GPU {
     let dev = Arc::new(Device::system_default().expect("Apple Metal GPU"));
     let cmds = Arc::new(dev.new_command_queue());
-    let state = Arc::new(gpu_state(Arc::clone(&dev)).unwrap());
-    GPU { dev, cmds, state }
+    let pipelines = gpu_pipelines(Arc::clone(&dev));
+    let counter_sample_buffer = gpu_counter_sample_buffer(&dev);
+    let timestamp_calibration = counter_sample_buffer.as_ref().map(|_| dev.sample_timestamps());
+    GPU {
+        dev,
+        cmds,
+        pipelines,
+        counter_sample_buffer,
+        timestamp_calibration,
+        pool: Mutex::new(HashMap::new()),
+    }
+}
+
+// Compute dispatches sample at dispatch boundaries, not stage boundaries
+// (stage boundaries are for render-pass vertex/fragment transitions).
+pub fn gpu_counter_sample_buffer(dev: &Arc<Device>) -> Option<CounterSampleBuffer> {
+    if !dev.supports_counter_sampling(MTLCounterSamplingPoint::AtDispatchBoundary) {
+        return None;
+    }
+    let timestamp_counter_set = dev
+        .counter_sets()
+        .iter()
+        .find(|set| set.name() == "timestamp")?
+        .to_owned();
+
+    let desc = CounterSampleBufferDescriptor::new();
+    desc.set_counter_set(&timestamp_counter_set);
+    desc.set_storage_mode(MTLStorageMode::Shared);
+    desc.set_sample_count(2);
+    dev.new_counter_sample_buffer_with_descriptor(&desc).ok()
 }
 
-pub fn gpu_result(buf_result: Buffer, len: usize) -> f32 {
+// None means "not measured" (no calibration baseline, or a degenerate
+// calibration window) rather than a real zero-length kernel — callers must
+// not treat it as 0ns.
+pub fn gpu_kernel_duration(gpu: &GPU, sample_buffer: &CounterSampleBuffer) -> Option<Duration> {
+    let (cpu_base, gpu_base) = gpu.timestamp_calibration?;
+
+    let samples = sample_buffer.resolve_counter_range(0..2);
+    let ticks = unsafe { slice::from_raw_parts(samples.as_ptr() as *const u64, 2) };
+    let gpu_ticks = ticks[1].saturating_sub(ticks[0]);
+
+    let (cpu_now, gpu_now) = gpu.dev.sample_timestamps();
+    let cpu_delta = cpu_now.saturating_sub(cpu_base);
+    let gpu_delta = gpu_now.saturating_sub(gpu_base);
+    if cpu_delta == 0 || gpu_delta == 0 {
+        return None;
+    }
+
+    let gpu_hz = gpu_delta as f64 / cpu_delta as f64;
+    Some(Duration::from_nanos((gpu_ticks as f64 / gpu_hz) as u64))
+}
+
+pub fn gpu_result(buf_result: &Buffer, len: usize) -> f32 {
     unsafe { slice::from_raw_parts(buf_result.contents() as *const f32, len) }
         .iter()
         .sum()
 }
 
-pub fn gpu_run(command_buffer: &CommandBufferRef, encoder: &ComputeCommandEncoderRef, len: usize) {
-    let threads_per_threadgroup: u64 = 64;
-    let threadgroups = (len as f64 / threads_per_threadgroup as f64).ceil() as u64;
-    encoder.dispatch_thread_groups(size_1d(threadgroups), size_1d(threads_per_threadgroup));
-    encoder.end_encoding();
-    command_buffer.commit();
-    command_buffer.wait_until_completed();
-}
+pub fn gpu_mem(
+    gpu: &GPU,
+    enc: &ComputeCommandEncoderRef,
+    a: &[f32],
+    b: &[f32],
+    threadgroups: u64,
+) -> (Buffer, Buffer, Buffer) {
+    let buf_a = gpu.acquire(a.len() * mem::size_of::<f32>());
+    let buf_b = gpu.acquire(b.len() * mem::size_of::<f32>());
+    let buf_result = gpu.acquire(threadgroups as usize * mem::size_of::<f32>());
+    gpu_write(&buf_a, a);
+    gpu_write(&buf_b, b);
 
-pub fn gpu_mem(dev: Arc<Device>, enc: &ComputeCommandEncoderRef, a: &[f32], b: &[f32]) -> Buffer {
-    let buf_result = gpu_buf(&dev, &vec![0.0; a.len()]);
-    enc.set_buffer(0, Some(&gpu_buf(&dev, a)), 0);
-    enc.set_buffer(1, Some(&gpu_buf(&dev, b)), 0);
+    let len = a.len() as u32;
+    enc.set_buffer(0, Some(&buf_a), 0);
+    enc.set_buffer(1, Some(&buf_b), 0);
     enc.set_buffer(2, Some(&buf_result), 0);
-    buf_result
+    enc.set_bytes(3, mem::size_of::<u32>() as u64, &len as *const u32 as *const ffi::c_void);
+    enc.set_threadgroup_memory_length(0, 64 * mem::size_of::<f32>() as u64);
+    (buf_a, buf_b, buf_result)
+}
+
+pub fn gpu_write(buf: &Buffer, data: &[f32]) {
+    unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), buf.contents() as *mut f32, data.len()) };
 }
 
 pub fn size_1d(width: u64) -> MTLSize {
-    let (height, depth) = (1, 1);
+    size_2d(width, 1)
+}
+
+pub fn size_2d(width: u64, height: u64) -> MTLSize {
     MTLSize {
         width,
         height,
-        depth,
+        depth: 1,
     }
 }
 
-pub fn gpu_buf(dev: &Arc<Device>, buf: &[f32]) -> Buffer {
-    dev.new_buffer_with_data(
-        buf.as_ptr() as *const ffi::c_void,
-        (buf.len() * std::mem::size_of::<f32>()) as u64,
-        MTLResourceOptions::StorageModeShared,
-    )
+pub fn gpu_matmul(a: &[f32], b: &[f32], m: u64, n: u64, k: u64, gpu: &GPU) -> Vec<f32> {
+    autoreleasepool(|| {
+        let mut g = graph::Graph::new();
+        let tensor_a = graph::Tensor::from_slice(gpu, m, k, a);
+        let tensor_b = graph::Tensor::from_slice(gpu, k, n, b);
+        g.push(graph::Op::MatMul(tensor_a.into(), tensor_b.into()));
+
+        let mut results = g.compute(gpu);
+        let out = results.pop().unwrap();
+        let result = out.to_vec();
+        gpu.release(out.buf);
+        result
+    })
+}
+
+pub fn gpu_alloc(dev: &Arc<Device>, byte_len: u64) -> Buffer {
+    dev.new_buffer(byte_len, MTLResourceOptions::StorageModeShared)
 }
 
 pub fn gpu_fn(device: &Arc<Device>, fn_name: String) -> Result<Function, String> {
@@ -121,9 +305,63 @@ pub fn gpu_fn(device: &Arc<Device>, fn_name: String) -> Result<Function, String>
     library.get_function(&fn_name, None)
 }
 
-pub fn gpu_state(device: Arc<Device>) -> Result<ComputePipelineState, String> {
-    let dot_fn = gpu_fn(&device, "dot_product".to_string()).unwrap();
+pub fn gpu_pipeline(device: &Arc<Device>, fn_name: &str) -> Result<ComputePipelineState, String> {
+    let function = gpu_fn(device, fn_name.to_string()).unwrap();
     let pipeline_desc = ComputePipelineDescriptor::new();
-    pipeline_desc.set_compute_function(Some(&dot_fn));
+    pipeline_desc.set_compute_function(Some(&function));
     device.new_compute_pipeline_state(&pipeline_desc)
 }
+
+// Builds every kernel's pipeline state once up front so ops just look theirs
+// up by function name instead of recompiling the library on every dispatch.
+pub fn gpu_pipelines(device: Arc<Device>) -> HashMap<String, Arc<ComputePipelineState>> {
+    ["dot_product", "elementwise_mul", "elementwise_add", "matmul"]
+        .iter()
+        .map(|&fn_name| {
+            let state = gpu_pipeline(&device, fn_name).unwrap();
+            (fn_name.to_string(), Arc::new(state))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_dot_sums_elementwise_products() {
+        let a = [1.0, 2.0, 3.0];
+        let b = [4.0, 5.0, 6.0];
+        assert_eq!(cpu_dot(&a, &b), 32.0);
+    }
+
+    #[test]
+    fn cpu_matmul_multiplies_rectangular_matrices() {
+        // 2x3 * 3x2
+        let a = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        let b = [7.0, 8.0, 9.0, 10.0, 11.0, 12.0];
+        let result = cpu_matmul(&a, &b, 2, 2, 3);
+        assert_eq!(result, vec![58.0, 64.0, 139.0, 154.0]);
+    }
+
+    #[test]
+    fn dot_approx_eq_allows_float_summation_drift_but_not_real_mismatches() {
+        assert!(dot_approx_eq(1_000_000.0, 1_000_000.5, 4));
+        assert!(!dot_approx_eq(1.0, 2.0, 4));
+    }
+
+    #[test]
+    fn dot_approx_eq_scales_tolerance_with_problem_size() {
+        // A tolerance tuned for a handful of elements would be far too tight
+        // to survive real accumulation-order drift at main()'s ~1e9-element
+        // scale, so a diff within the size-scaled bound has to pass there.
+        let len = 1_000_000_000;
+        let drift = 4.0 * (len as f32).sqrt() * f32::EPSILON;
+        assert!(dot_approx_eq(1.0, 1.0 + drift, len));
+    }
+
+    #[test]
+    fn dot_approx_eq_still_rejects_real_mismatches_at_scale() {
+        assert!(!dot_approx_eq(1.0, 2.0, 1_000_000_000));
+    }
+}